@@ -0,0 +1,427 @@
+//! Identify protocol.
+//!
+//! Identification runs over this protocol once a session completes its secio
+//! handshake: the dialer (client) sends an identify message carrying its `network_id`/
+//! `chain_id`, the `Multiaddr`s it is listening on and the remote address it
+//! observed, then waits for an explicit ack from the listener. Both sides
+//! compare the `network_id`; on mismatch the session is closed and an
+//! [`Event::NetworkIdNotMatch`] is surfaced — the identify analogue of the
+//! dial-path `PeerIdNotMatch` rejection.
+//!
+//! ## Identified sessions
+//!
+//! The identify handler marks a session identified, once the ack is
+//! exchanged, in an [`IdentifiedSessions`] handle shared with whoever
+//! constructed the [`IdentifyProtocol`], and removes it when the session
+//! closes; other code can consult [`IdentifiedSessions::is_identified`]. This
+//! crate does not itself hold back other protocols: their opening and
+//! `connected` callbacks are not gated here. The `network_id` is the value
+//! passed to [`IdentifyProtocol::new`].
+
+use fnv::FnvHashMap;
+use generic_channel::Sender;
+use log::{debug, warn};
+use p2p::{
+    context::{ServiceContext, SessionContext},
+    multiaddr::Multiaddr,
+    traits::{ProtocolMeta, ServiceProtocol},
+    PeerId, ProtocolId, SessionId, SessionType,
+};
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::codec::length_delimited::LengthDelimitedCodec;
+
+mod protocol;
+
+pub use crate::protocol::{IdentifyMessage, MessageType};
+
+/// Close a session that has not identified itself within this interval.
+const CHECK_TIMEOUT_TOKEN: u64 = 0;
+const CHECK_TIMEOUT_INTERVAL: Duration = Duration::from_secs(8);
+
+/// Opaque identifier for the network (a.k.a. chain-id) a node belongs to. Two
+/// peers only finish identification if their ids are equal.
+pub type NetworkId = u64;
+
+/// Shared set of sessions that have completed identification.
+///
+/// The identify handler marks sessions here and removes them when they close.
+/// It is cheap to clone (an `Arc` inside).
+#[derive(Clone, Default)]
+pub struct IdentifiedSessions {
+    inner: Arc<Mutex<HashSet<SessionId>>>,
+}
+
+impl IdentifiedSessions {
+    /// Whether `session` has completed identification.
+    pub fn is_identified(&self, session: SessionId) -> bool {
+        self.inner.lock().unwrap().contains(&session)
+    }
+
+    fn mark(&self, session: SessionId) {
+        self.inner.lock().unwrap().insert(session);
+    }
+
+    fn forget(&self, session: SessionId) {
+        self.inner.lock().unwrap().remove(&session);
+    }
+}
+
+/// Identify protocol events, reported to the caller over the event channel.
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// The session completed identification and belongs to our network; other
+    /// protocols may now be opened on it.
+    Identified(PeerId),
+    /// The remote advertised a different `network_id`; the session was closed.
+    /// This is the identify-protocol analogue of `PeerIdNotMatch`.
+    NetworkIdNotMatch(PeerId),
+}
+
+/// A side effect the handler wants the service context to carry out. Keeping
+/// the decision separate from the I/O lets the state machine be tested without
+/// a live `ServiceContext`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Action {
+    /// Send these bytes back on the identify protocol.
+    Send(Vec<u8>),
+    /// Close the session.
+    Disconnect,
+    /// Report an event to the caller.
+    Emit(EventKind),
+}
+
+/// The payload-free shape of [`Event`], used inside [`Action`] so decisions are
+/// comparable in tests; the handler pairs it with the peer id when emitting.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum EventKind {
+    Identified,
+    NetworkIdNotMatch,
+}
+
+/// Per-session identification state, kept until the ack is exchanged.
+#[derive(Clone, Debug)]
+struct RemoteInfo {
+    /// The peer behind this session.
+    peer_id: PeerId,
+    /// Whether we have received (and accepted) the remote's identify message.
+    identified: bool,
+    /// Whether the ack has been exchanged in our favor.
+    acked: bool,
+    /// The address the remote observed for us, echoed back for NAT detection.
+    observed_addr: Multiaddr,
+    /// The listen addresses the remote advertised.
+    listen_addrs: Vec<Multiaddr>,
+}
+
+/// Builder-side metadata for the identify protocol.
+pub struct IdentifyProtocol<S: Sender<Event> + Send + Clone> {
+    id: ProtocolId,
+    network_id: NetworkId,
+    listen_addrs: Vec<Multiaddr>,
+    identified: IdentifiedSessions,
+    event_sender: S,
+}
+
+impl<S> IdentifyProtocol<S>
+where
+    S: Sender<Event> + Send + Clone,
+{
+    pub fn new(
+        id: ProtocolId,
+        network_id: NetworkId,
+        listen_addrs: Vec<Multiaddr>,
+        identified: IdentifiedSessions,
+        event_sender: S,
+    ) -> Self {
+        IdentifyProtocol {
+            id,
+            network_id,
+            listen_addrs,
+            identified,
+            event_sender,
+        }
+    }
+}
+
+impl<S> ProtocolMeta<LengthDelimitedCodec> for IdentifyProtocol<S>
+where
+    S: Sender<Event> + Send + Clone + 'static,
+{
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        let handle = Box::new(IdentifyHandler {
+            proto_id: self.id,
+            network_id: self.network_id,
+            listen_addrs: self.listen_addrs.clone(),
+            remotes: Default::default(),
+            identified: self.identified.clone(),
+            event_sender: self.event_sender.clone(),
+        });
+        Some(handle)
+    }
+}
+
+struct IdentifyHandler<S: Sender<Event>> {
+    proto_id: ProtocolId,
+    network_id: NetworkId,
+    listen_addrs: Vec<Multiaddr>,
+    remotes: FnvHashMap<SessionId, RemoteInfo>,
+    identified: IdentifiedSessions,
+    event_sender: S,
+}
+
+impl<S: Sender<Event>> IdentifyHandler<S> {
+    fn send_event(&mut self, event: Event) {
+        if let Err(err) = self.event_sender.try_send(event) {
+            warn!("send identify event error: {}", err);
+        }
+    }
+
+    /// The message the dialer sends first, or `None` for the listener (which
+    /// speaks only to ack).
+    fn initial_message(&self, ty: SessionType, observed: Multiaddr) -> Option<Vec<u8>> {
+        if ty == SessionType::Outbound {
+            Some(IdentifyMessage::identify(self.network_id, self.listen_addrs.clone(), observed).encode())
+        } else {
+            None
+        }
+    }
+
+    /// Process one inbound identify frame, mutating per-session state and
+    /// returning the side effects to apply. Pure with respect to the
+    /// `ServiceContext`, so it can be driven directly in tests.
+    fn on_message(&mut self, session: SessionId, data: &[u8]) -> Vec<Action> {
+        if !self.remotes.contains_key(&session) {
+            return Vec::new();
+        }
+
+        let msg = match IdentifyMessage::decode(data) {
+            Some(msg) => msg,
+            None => return vec![Action::Disconnect],
+        };
+
+        if msg.network_id != self.network_id {
+            // Mirror the PeerIdNotMatch close path with a distinct event.
+            return vec![Action::Disconnect, Action::Emit(EventKind::NetworkIdNotMatch)];
+        }
+
+        let mut actions = Vec::new();
+        match msg.ty {
+            MessageType::Identify => {
+                if let Some(info) = self.remotes.get_mut(&session) {
+                    info.identified = true;
+                    info.listen_addrs = msg.listen_addrs;
+                    info.observed_addr = msg.observed_addr;
+                    // Sending the ack completes the listener's side.
+                    info.acked = true;
+                }
+                actions.push(Action::Send(IdentifyMessage::ack(self.network_id).encode()));
+            }
+            MessageType::Ack => {
+                if let Some(info) = self.remotes.get_mut(&session) {
+                    info.identified = true;
+                    info.acked = true;
+                }
+            }
+        }
+
+        if self.is_identified(session) {
+            self.identified.mark(session);
+            actions.push(Action::Emit(EventKind::Identified));
+        }
+        actions
+    }
+
+    /// A session is fully identified once we accepted the remote's identify
+    /// message and the ack has been exchanged.
+    fn is_identified(&self, id: SessionId) -> bool {
+        self.remotes
+            .get(&id)
+            .map(|info| info.identified && info.acked)
+            .unwrap_or(false)
+    }
+}
+
+impl<S> ServiceProtocol for IdentifyHandler<S>
+where
+    S: Sender<Event>,
+{
+    fn init(&mut self, control: &mut ServiceContext) {
+        control.set_service_notify(self.proto_id, CHECK_TIMEOUT_INTERVAL, CHECK_TIMEOUT_TOKEN);
+    }
+
+    fn connected(&mut self, control: &mut ServiceContext, session: &SessionContext, version: &str) {
+        let peer_id = match session.remote_pubkey {
+            Some(ref pubkey) => pubkey.peer_id(),
+            None => {
+                control.disconnect(session.id);
+                return;
+            }
+        };
+        debug!(
+            "identify open on session [{}], address: [{}], type: [{:?}], version: {}",
+            session.id, session.address, session.ty, version
+        );
+        self.remotes.entry(session.id).or_insert_with(|| RemoteInfo {
+            peer_id,
+            identified: false,
+            acked: false,
+            observed_addr: session.address.clone(),
+            listen_addrs: Vec::new(),
+        });
+
+        if let Some(msg) = self.initial_message(session.ty, session.address.clone()) {
+            control.send_message(session.id, self.proto_id, msg);
+        }
+    }
+
+    fn disconnected(&mut self, _control: &mut ServiceContext, session: &SessionContext) {
+        self.remotes.remove(&session.id);
+        self.identified.forget(session.id);
+    }
+
+    fn received(&mut self, control: &mut ServiceContext, session: &SessionContext, data: Vec<u8>) {
+        let peer_id = match self.remotes.get(&session.id) {
+            Some(info) => info.peer_id.clone(),
+            None => return,
+        };
+        for action in self.on_message(session.id, &data) {
+            match action {
+                Action::Send(bytes) => control.send_message(session.id, self.proto_id, bytes),
+                Action::Disconnect => control.disconnect(session.id),
+                Action::Emit(EventKind::Identified) => {
+                    debug!("session [{}] identified", session.id);
+                    self.send_event(Event::Identified(peer_id.clone()));
+                }
+                Action::Emit(EventKind::NetworkIdNotMatch) => {
+                    warn!("session [{}] network id mismatch", session.id);
+                    self.send_event(Event::NetworkIdNotMatch(peer_id.clone()));
+                }
+            }
+        }
+    }
+
+    fn notify(&mut self, control: &mut ServiceContext, token: u64) {
+        if token == CHECK_TIMEOUT_TOKEN {
+            let unidentified: Vec<SessionId> = self
+                .remotes
+                .iter()
+                .filter(|(_, info)| !(info.identified && info.acked))
+                .map(|(id, _)| *id)
+                .collect();
+            for id in unidentified {
+                debug!("session [{}] not identified in time, closing", id);
+                control.disconnect(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fixture {
+        handler: IdentifyHandler<crossbeam_channel::Sender<Event>>,
+        events: crossbeam_channel::Receiver<Event>,
+    }
+
+    fn fixture(network_id: NetworkId) -> Fixture {
+        let (sender, events) = crossbeam_channel::unbounded();
+        Fixture {
+            handler: IdentifyHandler {
+                proto_id: 0.into(),
+                network_id,
+                listen_addrs: vec!["/ip4/127.0.0.1/tcp/1".parse().unwrap()],
+                remotes: Default::default(),
+                identified: IdentifiedSessions::default(),
+                event_sender: sender,
+            },
+            events,
+        }
+    }
+
+    fn open(handler: &mut IdentifyHandler<crossbeam_channel::Sender<Event>>, session: SessionId) {
+        handler.remotes.insert(
+            session,
+            RemoteInfo {
+                peer_id: PeerId::random(),
+                identified: false,
+                acked: false,
+                observed_addr: Multiaddr::default(),
+                listen_addrs: Vec::new(),
+            },
+        );
+    }
+
+    #[test]
+    fn listener_acks_a_matching_identify_and_marks_identified() {
+        let mut fx = fixture(1);
+        let session = 7.into();
+        open(&mut fx.handler, session);
+
+        let identify = IdentifyMessage::identify(1, vec![], Multiaddr::default()).encode();
+        let actions = fx.handler.on_message(session, &identify);
+
+        assert!(matches!(actions[0], Action::Send(_)));
+        assert!(actions.contains(&Action::Emit(EventKind::Identified)));
+        assert!(fx.handler.identified.is_identified(session));
+    }
+
+    #[test]
+    fn mismatched_network_disconnects_and_reports() {
+        let mut fx = fixture(1);
+        let session = 7.into();
+        open(&mut fx.handler, session);
+
+        let foreign = IdentifyMessage::identify(999, vec![], Multiaddr::default()).encode();
+        let actions = fx.handler.on_message(session, &foreign);
+
+        assert_eq!(
+            actions,
+            vec![Action::Disconnect, Action::Emit(EventKind::NetworkIdNotMatch)]
+        );
+        assert!(!fx.handler.identified.is_identified(session));
+    }
+
+    #[test]
+    fn dialer_ack_completes_without_resending() {
+        let mut fx = fixture(1);
+        let session = 7.into();
+        open(&mut fx.handler, session);
+
+        let ack = IdentifyMessage::ack(1).encode();
+        let actions = fx.handler.on_message(session, &ack);
+
+        // The dialer sends nothing in response to an ack, just completes.
+        assert_eq!(actions, vec![Action::Emit(EventKind::Identified)]);
+        assert!(fx.handler.identified.is_identified(session));
+        // on_message only returns actions and sends nothing on the event
+        // channel; the receiver is simply dropped.
+        drop(fx.events);
+    }
+
+    #[test]
+    fn only_the_dialer_speaks_first() {
+        let fx = fixture(1);
+        assert!(fx
+            .handler
+            .initial_message(SessionType::Outbound, Multiaddr::default())
+            .is_some());
+        assert!(fx
+            .handler
+            .initial_message(SessionType::Inbound, Multiaddr::default())
+            .is_none());
+    }
+}
@@ -0,0 +1,180 @@
+//! Wire format for the identify protocol.
+//!
+//! A message is a one-byte [`MessageType`] discriminant, the 8-byte big-endian
+//! network id, the observed address, a 4-byte big-endian count and that many
+//! listen addresses; each address is a 4-byte big-endian length followed by
+//! its bytes. Keeping the encoding hand-rolled (rather than pulling
+//! in a flatbuffers schema) mirrors how small the identify payload is: a
+//! network id, the listen addresses and the observed remote address.
+
+use p2p::multiaddr::Multiaddr;
+use std::convert::TryInto;
+
+use crate::NetworkId;
+
+/// Discriminates the identification request from its acknowledgement.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MessageType {
+    /// Client advertises itself and waits for an ack.
+    Identify,
+    /// Server (or peer) confirms the network matches.
+    Ack,
+}
+
+impl MessageType {
+    fn to_byte(self) -> u8 {
+        match self {
+            MessageType::Identify => 0,
+            MessageType::Ack => 1,
+        }
+    }
+
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(MessageType::Identify),
+            1 => Some(MessageType::Ack),
+            _ => None,
+        }
+    }
+}
+
+/// An identification message exchanged during the identify handshake.
+#[derive(Clone, Debug)]
+pub struct IdentifyMessage {
+    pub ty: MessageType,
+    pub network_id: NetworkId,
+    pub listen_addrs: Vec<Multiaddr>,
+    pub observed_addr: Multiaddr,
+}
+
+impl IdentifyMessage {
+    pub fn identify(
+        network_id: NetworkId,
+        listen_addrs: Vec<Multiaddr>,
+        observed_addr: Multiaddr,
+    ) -> Self {
+        IdentifyMessage {
+            ty: MessageType::Identify,
+            network_id,
+            listen_addrs,
+            observed_addr,
+        }
+    }
+
+    pub fn ack(network_id: NetworkId) -> Self {
+        IdentifyMessage {
+            ty: MessageType::Ack,
+            network_id,
+            listen_addrs: Vec::new(),
+            observed_addr: Multiaddr::default(),
+        }
+    }
+
+    /// Serialize the message for the wire.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        buf.push(self.ty.to_byte());
+        buf.extend_from_slice(&self.network_id.to_be_bytes());
+        put_addr(&mut buf, &self.observed_addr);
+        buf.extend_from_slice(&(self.listen_addrs.len() as u32).to_be_bytes());
+        for addr in &self.listen_addrs {
+            put_addr(&mut buf, addr);
+        }
+        buf
+    }
+
+    /// Parse a message previously produced by [`IdentifyMessage::encode`],
+    /// returning `None` on any truncation or bad discriminant.
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        let mut cur = 0usize;
+        let ty = MessageType::from_byte(*data.get(cur)?)?;
+        cur += 1;
+        let network_id = NetworkId::from_be_bytes(take(data, &mut cur, 8)?.try_into().ok()?);
+        let observed_addr = get_addr(data, &mut cur)?;
+        let count = u32::from_be_bytes(take(data, &mut cur, 4)?.try_into().ok()?) as usize;
+        // Each address is at least its 4-byte length prefix, so a count larger
+        // than a quarter of the remaining bytes is impossible — reject it before reserving
+        // to avoid a peer requesting a multi-gigabyte allocation.
+        let remaining = data.len().saturating_sub(cur);
+        if count > remaining / 4 {
+            return None;
+        }
+        let mut listen_addrs = Vec::with_capacity(count);
+        for _ in 0..count {
+            listen_addrs.push(get_addr(data, &mut cur)?);
+        }
+        Some(IdentifyMessage {
+            ty,
+            network_id,
+            listen_addrs,
+            observed_addr,
+        })
+    }
+}
+
+fn put_addr(buf: &mut Vec<u8>, addr: &Multiaddr) {
+    let bytes = addr.to_vec();
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(&bytes);
+}
+
+fn get_addr(data: &[u8], cur: &mut usize) -> Option<Multiaddr> {
+    let len = u32::from_be_bytes(take(data, cur, 4)?.try_into().ok()?) as usize;
+    let bytes = take(data, cur, len)?;
+    Multiaddr::try_from(bytes.to_vec()).ok()
+}
+
+fn take<'a>(data: &'a [u8], cur: &mut usize, len: usize) -> Option<&'a [u8]> {
+    let end = cur.checked_add(len)?;
+    let slice = data.get(*cur..end)?;
+    *cur = end;
+    Some(slice)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> Multiaddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn identify_round_trips() {
+        let msg = IdentifyMessage::identify(
+            42,
+            vec![addr("/ip4/127.0.0.1/tcp/1337"), addr("/ip4/10.0.0.1/tcp/1")],
+            addr("/ip4/1.2.3.4/tcp/9"),
+        );
+        let decoded = IdentifyMessage::decode(&msg.encode()).unwrap();
+        assert_eq!(decoded.ty, MessageType::Identify);
+        assert_eq!(decoded.network_id, 42);
+        assert_eq!(decoded.observed_addr, addr("/ip4/1.2.3.4/tcp/9"));
+        assert_eq!(decoded.listen_addrs.len(), 2);
+    }
+
+    #[test]
+    fn ack_round_trips() {
+        let decoded = IdentifyMessage::decode(&IdentifyMessage::ack(7).encode()).unwrap();
+        assert_eq!(decoded.ty, MessageType::Ack);
+        assert_eq!(decoded.network_id, 7);
+        assert!(decoded.listen_addrs.is_empty());
+    }
+
+    #[test]
+    fn truncated_message_is_rejected() {
+        let bytes = IdentifyMessage::identify(1, vec![addr("/ip4/127.0.0.1/tcp/1")], addr("/ip4/1.2.3.4/tcp/9")).encode();
+        assert!(IdentifyMessage::decode(&bytes[..bytes.len() - 3]).is_none());
+        assert!(IdentifyMessage::decode(&[]).is_none());
+    }
+
+    #[test]
+    fn oversized_addr_count_is_rejected() {
+        // type + network_id + empty observed addr + absurd count, no bodies.
+        let mut bytes = vec![MessageType::Identify.to_byte()];
+        bytes.extend_from_slice(&1u64.to_be_bytes());
+        bytes.extend_from_slice(&0u32.to_be_bytes()); // observed addr len 0
+        bytes.extend_from_slice(&u32::MAX.to_be_bytes()); // count
+        assert!(IdentifyMessage::decode(&bytes).is_none());
+    }
+}
@@ -0,0 +1,10 @@
+//! multistream-select protocol negotiation.
+//!
+//! This snapshot carries the simultaneous-open extension used for NAT hole
+//! punching. It is declared here so [`sim_open::Version::V1SimOpen`] and the
+//! [`sim_open::SimOpenNegotiator`] are reachable from the negotiation code
+//! path rather than compiling into nothing.
+
+pub mod sim_open;
+
+pub use crate::sim_open::{Role, SimOpenNegotiator, SimOpenOutcome, SimOpenToken, Version};
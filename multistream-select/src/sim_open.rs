@@ -0,0 +1,249 @@
+//! Simultaneous-open extension for protocol negotiation.
+//!
+//! Ordinary multistream-select assumes a clear dialer/listener split: the
+//! dialer proposes protocols with `ls`/protocol lines and the listener
+//! responds. That breaks during NAT hole punching, where both peers dial each
+//! other at the same instant and so both believe they are the dialer.
+//!
+//! [`Version::V1SimOpen`] resolves this. When enabled, instead of immediately
+//! sending the dialer's protocol line, each side sends an `iamclient`/`select`
+//! token together with a random 64-bit nonce. If both peers announce they are
+//! initiators, the peer with the larger nonce becomes the effective dialer and
+//! the other the responder; negotiation then continues normally from the
+//! assigned roles. If the remote does not advertise the extension we fall back
+//! transparently to the classic behavior.
+
+/// Negotiation protocol version.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Version {
+    /// Classic multistream-select 1.0 with a fixed dialer/listener split.
+    V1,
+    /// 1.0 plus the simultaneous-open extension for coordinated dials.
+    V1SimOpen,
+}
+
+impl Default for Version {
+    fn default() -> Self {
+        Version::V1
+    }
+}
+
+/// Token a peer sends under the simultaneous-open extension.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SimOpenToken {
+    /// "I am an initiator" — both sides send this plus a nonce when dialing.
+    IamClient { nonce: u64 },
+    /// "I am the elected initiator, you take the listener role" — sent by the
+    /// peer that wins the nonce tiebreak to the one that loses it.
+    Select,
+}
+
+impl SimOpenToken {
+    /// The wire line for this token, without the trailing newline the codec
+    /// appends.
+    pub fn as_line(&self) -> String {
+        match self {
+            SimOpenToken::IamClient { nonce } => format!("iamclient {}", nonce),
+            SimOpenToken::Select => "select".to_string(),
+        }
+    }
+
+    /// Parse a token previously produced by [`SimOpenToken::as_line`].
+    pub fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        if line == "select" {
+            return Some(SimOpenToken::Select);
+        }
+        let nonce = line.strip_prefix("iamclient ")?.trim().parse().ok()?;
+        Some(SimOpenToken::IamClient { nonce })
+    }
+}
+
+/// The role a peer takes once the simultaneous-open tiebreak is resolved.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Role {
+    /// This peer drives protocol selection.
+    Initiator,
+    /// This peer responds to the elected initiator.
+    Responder,
+}
+
+/// Elect a single initiator from the two nonces exchanged during a
+/// simultaneous open.
+///
+/// The peer with the larger nonce wins and becomes the [`Role::Initiator`];
+/// the other becomes the [`Role::Responder`]. A nonce tie is resolved in
+/// favor of the responder so neither side can deadlock assuming it won — the
+/// caller should then retry with a fresh nonce.
+pub fn resolve_role(local_nonce: u64, remote_nonce: u64) -> Role {
+    if local_nonce > remote_nonce {
+        Role::Initiator
+    } else {
+        Role::Responder
+    }
+}
+
+/// Whether a tie occurred and the open must be retried with fresh nonces.
+pub fn is_tie(local_nonce: u64, remote_nonce: u64) -> bool {
+    local_nonce == remote_nonce
+}
+
+/// Outcome of feeding the remote's first line to a [`SimOpenNegotiator`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum SimOpenOutcome {
+    /// Both peers advertised the extension and a single initiator was elected.
+    /// Continue normal negotiation from `role`, first sending `reply` if set
+    /// (the elected initiator tells the responder to take the listener role).
+    Role {
+        role: Role,
+        reply: Option<SimOpenToken>,
+    },
+    /// Both nonces were equal; discard and restart the open with fresh nonces.
+    Retry,
+    /// The remote did not advertise the extension, so it is speaking classic
+    /// multistream-select. Fall back transparently, keeping our dialer role.
+    Fallback(Role),
+}
+
+/// Drives the simultaneous-open handshake for one side of a connection.
+///
+/// Construct with our random `nonce`, emit [`SimOpenNegotiator::initial_line`]
+/// when [`Version::V1SimOpen`] is in effect, then feed the remote's first line
+/// to [`SimOpenNegotiator::on_remote`] to learn the elected role. Callers that
+/// negotiated plain [`Version::V1`] skip this type entirely.
+pub struct SimOpenNegotiator {
+    nonce: u64,
+}
+
+impl SimOpenNegotiator {
+    pub fn new(nonce: u64) -> Self {
+        SimOpenNegotiator { nonce }
+    }
+
+    /// The token this side sends first: "I am an initiator" plus our nonce.
+    pub fn initial_line(&self) -> String {
+        SimOpenToken::IamClient { nonce: self.nonce }.as_line()
+    }
+
+    /// Resolve the role from the remote's first line.
+    ///
+    /// * A remote `iamclient` means both peers are initiators: the larger
+    ///   nonce wins the tiebreak (ties return [`SimOpenOutcome::Retry`]). The
+    ///   elected initiator replies with [`SimOpenToken::Select`] so the
+    ///   responder knows to become the listener.
+    /// * Any line that is not a simultaneous-open token means the remote does
+    ///   not advertise the extension; we fall back to classic behavior,
+    ///   keeping the initiator role we already hold as the dialer.
+    pub fn on_remote(&self, line: &str) -> SimOpenOutcome {
+        match SimOpenToken::parse(line) {
+            Some(SimOpenToken::IamClient { nonce }) => {
+                if is_tie(self.nonce, nonce) {
+                    SimOpenOutcome::Retry
+                } else {
+                    let role = resolve_role(self.nonce, nonce);
+                    let reply = match role {
+                        Role::Initiator => Some(SimOpenToken::Select),
+                        Role::Responder => None,
+                    };
+                    SimOpenOutcome::Role { role, reply }
+                }
+            }
+            // A bare `select` or an unrecognised (classic) line: the remote is
+            // not playing the sim-open game, so negotiate as usual.
+            _ => SimOpenOutcome::Fallback(Role::Initiator),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn larger_nonce_becomes_initiator() {
+        assert_eq!(resolve_role(9, 3), Role::Initiator);
+        assert_eq!(resolve_role(3, 9), Role::Responder);
+        assert!(is_tie(5, 5));
+    }
+
+    #[test]
+    fn token_lines_round_trip() {
+        let line = SimOpenToken::IamClient { nonce: 42 }.as_line();
+        assert_eq!(SimOpenToken::parse(&line), Some(SimOpenToken::IamClient { nonce: 42 }));
+        assert_eq!(SimOpenToken::parse("select"), Some(SimOpenToken::Select));
+        assert_eq!(SimOpenToken::parse("/ipfs/id/1.0.0"), None);
+    }
+
+    #[test]
+    fn both_initiators_elect_one() {
+        let local = SimOpenNegotiator::new(100);
+        let remote = SimOpenNegotiator::new(7);
+        // The higher nonce side wins and tells the other to listen.
+        assert_eq!(
+            local.on_remote(&remote.initial_line()),
+            SimOpenOutcome::Role {
+                role: Role::Initiator,
+                reply: Some(SimOpenToken::Select),
+            }
+        );
+        assert_eq!(
+            remote.on_remote(&local.initial_line()),
+            SimOpenOutcome::Role {
+                role: Role::Responder,
+                reply: None,
+            }
+        );
+    }
+
+    #[test]
+    fn nonce_tie_requests_retry() {
+        let local = SimOpenNegotiator::new(5);
+        let remote = SimOpenNegotiator::new(5);
+        assert_eq!(local.on_remote(&remote.initial_line()), SimOpenOutcome::Retry);
+    }
+
+    #[test]
+    fn classic_remote_falls_back_transparently() {
+        let local = SimOpenNegotiator::new(1);
+        // A remote that never advertises the extension just sends a protocol
+        // line; we keep our dialer (initiator) role.
+        assert_eq!(
+            local.on_remote("/ipfs/ping/1.0.0"),
+            SimOpenOutcome::Fallback(Role::Initiator)
+        );
+    }
+
+    // Drive both sides of a simultaneous open to agreement, exchanging the
+    // actual wire lines, and return each side's final role.
+    fn negotiate(a_nonce: u64, b_nonce: u64) -> (Role, Role) {
+        let a = SimOpenNegotiator::new(a_nonce);
+        let b = SimOpenNegotiator::new(b_nonce);
+        let a_line = a.initial_line();
+        let b_line = b.initial_line();
+
+        let role = |me: &SimOpenNegotiator, peer_line: &str, my_reply_expected| match me
+            .on_remote(peer_line)
+        {
+            SimOpenOutcome::Role { role, reply } => {
+                // Exactly one side emits the Select reply: the initiator.
+                assert_eq!(reply.is_some(), my_reply_expected);
+                if let Some(token) = reply {
+                    assert_eq!(token, SimOpenToken::Select);
+                    assert_eq!(role, Role::Initiator);
+                }
+                role
+            }
+            other => panic!("unexpected outcome {:?}", other),
+        };
+
+        let a_role = role(&a, &b_line, a_nonce > b_nonce);
+        let b_role = role(&b, &a_line, b_nonce > a_nonce);
+        (a_role, b_role)
+    }
+
+    #[test]
+    fn end_to_end_elects_exactly_one_initiator() {
+        assert_eq!(negotiate(100, 7), (Role::Initiator, Role::Responder));
+        assert_eq!(negotiate(7, 100), (Role::Responder, Role::Initiator));
+    }
+}
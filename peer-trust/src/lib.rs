@@ -0,0 +1,287 @@
+//! Peer trust-metric subsystem.
+//!
+//! The ping protocol already surfaces `Event::Timeout` and
+//! `Event::UnexpectedError`, but nothing turns repeated misbehavior into
+//! disconnection and ban decisions. This module maintains a per-[`PeerId`]
+//! score fed through [`TrustMetric::report_peer`]. Good behavior raises the
+//! score, bad behavior lowers it, and the score decays toward the baseline
+//! over time so a peer can recover from an isolated hiccup.
+//!
+//! When a peer's score drops below the ban threshold, `report_peer` returns
+//! [`ReportOutcome::Banned`] with the end of a configurable cool-off period,
+//! and [`TrustMetric::is_banned`] / [`TrustMetric::should_dial`] report the
+//! peer as banned until then. This crate only keeps scores and ban state: it
+//! does not disconnect peers, emit service events or take part in dialing,
+//! and it is not called from `ServiceContext`, `ProtocolContext` or the ping
+//! protocol.
+
+use fnv::FnvHashMap;
+use p2p::PeerId;
+use std::time::{Duration, Instant};
+
+/// Starting score for a freshly seen peer, and the value scores decay toward.
+const DEFAULT_BASELINE: i32 = 100;
+
+/// Feedback a protocol handler reports about a peer's behavior.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Feedback {
+    /// A valid, timely pong.
+    GoodPong,
+    /// The peer failed the consecutive-failure ping threshold.
+    Timeout,
+    /// A protocol frame failed to decode.
+    ProtocolDecodeError,
+    /// Identification / peer-id handshake mismatch.
+    HandshakeMismatch,
+}
+
+/// What the service should do after a [`TrustMetric::report_peer`] call.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReportOutcome {
+    /// Nothing to do; the peer stays connected.
+    Ok,
+    /// The peer just crossed the ban threshold; [`TrustMetric::is_banned`]
+    /// reports it as banned until `until`.
+    Banned { until: Instant },
+}
+
+impl Feedback {
+    /// How much this feedback shifts a peer's score.
+    fn delta(self) -> i32 {
+        match self {
+            Feedback::GoodPong => 1,
+            Feedback::Timeout => -20,
+            Feedback::ProtocolDecodeError => -30,
+            Feedback::HandshakeMismatch => -101,
+        }
+    }
+}
+
+/// Tunable thresholds for the trust metric, passed to [`TrustMetric::new`] so
+/// a deployment can trade tolerance against responsiveness.
+#[derive(Clone, Copy, Debug)]
+pub struct TrustConfig {
+    /// Score a new peer starts at and decays toward.
+    pub baseline: i32,
+    /// A peer is banned once its score drops below this.
+    pub ban_threshold: i32,
+    /// How long a banned peer is refused redials.
+    pub ban_duration: Duration,
+    /// Score recovered per second back toward the baseline.
+    pub decay_per_second: i32,
+}
+
+impl Default for TrustConfig {
+    fn default() -> Self {
+        TrustConfig {
+            baseline: DEFAULT_BASELINE,
+            ban_threshold: 0,
+            ban_duration: Duration::from_secs(300),
+            decay_per_second: 1,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct PeerScore {
+    score: i32,
+    last_update: Instant,
+    /// When the ban lifts, if the peer is currently banned.
+    banned_until: Option<Instant>,
+}
+
+/// Per-peer score store driving disconnect/ban decisions.
+pub struct TrustMetric {
+    config: TrustConfig,
+    scores: FnvHashMap<PeerId, PeerScore>,
+}
+
+impl TrustMetric {
+    pub fn new(config: TrustConfig) -> Self {
+        TrustMetric {
+            config,
+            scores: Default::default(),
+        }
+    }
+
+    /// Apply `feedback` to `peer`'s score and report what the service should do
+    /// as a result.
+    ///
+    /// The ping protocol does not call this: `PingHandler::send_event` only
+    /// forwards `Event::Timeout`/`Event::UnexpectedError` on its event
+    /// channel, and nothing maps those events to [`Feedback`]. Acting on
+    /// [`ReportOutcome::Banned`] is up to the caller.
+    pub fn report_peer(&mut self, peer: PeerId, feedback: Feedback, now: Instant) -> ReportOutcome {
+        let config = self.config;
+        let entry = self.scores.entry(peer).or_insert_with(|| PeerScore {
+            score: config.baseline,
+            last_update: now,
+            banned_until: None,
+        });
+        entry.decay(&config, now);
+        entry.score = (entry.score + feedback.delta()).min(config.baseline);
+
+        if entry.score < config.ban_threshold && entry.banned_until.is_none() {
+            entry.banned_until = Some(now + config.ban_duration);
+            ReportOutcome::Banned {
+                until: now + config.ban_duration,
+            }
+        } else {
+            ReportOutcome::Ok
+        }
+    }
+
+    /// Whether `peer` may be dialed, i.e. it is not currently banned.
+    pub fn should_dial(&mut self, peer: &PeerId, now: Instant) -> bool {
+        !self.is_banned(peer, now)
+    }
+
+    /// Whether `peer` is currently banned; clears an expired ban as a side
+    /// effect so the cool-off is self-healing.
+    pub fn is_banned(&mut self, peer: &PeerId, now: Instant) -> bool {
+        match self.scores.get_mut(peer) {
+            Some(entry) => match entry.banned_until {
+                Some(until) if now < until => true,
+                Some(_) => {
+                    // Ban elapsed: lift it and reset to baseline so the peer
+                    // gets a clean slate.
+                    entry.banned_until = None;
+                    entry.score = self.config.baseline;
+                    entry.last_update = now;
+                    false
+                }
+                None => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Current score for a peer, after applying pending decay.
+    pub fn score(&mut self, peer: &PeerId, now: Instant) -> i32 {
+        let config = self.config;
+        match self.scores.get_mut(peer) {
+            Some(entry) => {
+                entry.decay(&config, now);
+                entry.score
+            }
+            None => config.baseline,
+        }
+    }
+}
+
+impl PeerScore {
+    /// Recover the score toward the baseline based on elapsed time. Banned
+    /// peers do not decay until the ban is lifted.
+    fn decay(&mut self, config: &TrustConfig, now: Instant) {
+        if self.banned_until.is_some() {
+            return;
+        }
+        let elapsed = now.duration_since(self.last_update).as_secs() as i32;
+        if elapsed > 0 {
+            let recovered = elapsed.saturating_mul(config.decay_per_second);
+            if self.score < config.baseline {
+                self.score = (self.score + recovered).min(config.baseline);
+            }
+            self.last_update = now;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric() -> TrustMetric {
+        TrustMetric::new(TrustConfig {
+            baseline: 100,
+            ban_threshold: 0,
+            ban_duration: Duration::from_secs(300),
+            decay_per_second: 1,
+        })
+    }
+
+    #[test]
+    fn handshake_mismatch_bans_immediately() {
+        let mut m = metric();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        // -101 from baseline 100 -> -1, which is below the threshold of 0.
+        assert!(matches!(
+            m.report_peer(peer.clone(), Feedback::HandshakeMismatch, now),
+            ReportOutcome::Banned { .. }
+        ));
+        assert!(m.is_banned(&peer, now));
+        // The dial path now refuses the peer fast.
+        assert!(!m.should_dial(&peer, now));
+    }
+
+    #[test]
+    fn a_single_timeout_does_not_ban() {
+        let mut m = metric();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        assert_eq!(
+            m.report_peer(peer.clone(), Feedback::Timeout, now),
+            ReportOutcome::Ok
+        );
+        assert!(m.should_dial(&peer, now));
+        assert_eq!(m.score(&peer, now), 80);
+    }
+
+    #[test]
+    fn repeated_misbehavior_crosses_threshold() {
+        let mut m = metric();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        // Four decode errors at -30 each (no decay at the same instant) = -120.
+        let mut banned = false;
+        for _ in 0..4 {
+            banned |= matches!(
+                m.report_peer(peer.clone(), Feedback::ProtocolDecodeError, now),
+                ReportOutcome::Banned { .. }
+            );
+        }
+        assert!(banned);
+        assert!(m.is_banned(&peer, now));
+    }
+
+    #[test]
+    fn banning_one_peer_does_not_affect_another() {
+        let mut m = metric();
+        let (bad, good) = (PeerId::random(), PeerId::random());
+        let now = Instant::now();
+        m.report_peer(bad.clone(), Feedback::HandshakeMismatch, now);
+        assert!(!m.should_dial(&bad, now));
+        assert!(m.should_dial(&good, now));
+    }
+
+    #[test]
+    fn ban_lifts_after_cool_off() {
+        let mut m = metric();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        let until = match m.report_peer(peer.clone(), Feedback::HandshakeMismatch, now) {
+            ReportOutcome::Banned { until } => until,
+            ReportOutcome::Ok => panic!("expected ban"),
+        };
+        assert!(m.is_banned(&peer, until - Duration::from_secs(1)));
+        // After the cool-off the ban lifts and the score resets to baseline.
+        assert!(!m.is_banned(&peer, until + Duration::from_secs(1)));
+        assert_eq!(m.score(&peer, until + Duration::from_secs(1)), 100);
+        assert!(m.should_dial(&peer, until + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn score_decays_toward_baseline() {
+        let mut m = metric();
+        let peer = PeerId::random();
+        let now = Instant::now();
+        m.report_peer(peer.clone(), Feedback::Timeout, now); // 80
+        assert_eq!(m.score(&peer, now + Duration::from_secs(10)), 90);
+        // A good pong never pushes above the baseline.
+        for _ in 0..50 {
+            m.report_peer(peer.clone(), Feedback::GoodPong, now + Duration::from_secs(10));
+        }
+        assert_eq!(m.score(&peer, now + Duration::from_secs(10)), 100);
+    }
+}
@@ -20,15 +20,25 @@ use std::{
 use tokio::codec::length_delimited::LengthDelimitedCodec;
 
 const SEND_PING_TOKEN: u64 = 0;
-const CHECK_TIMEOUT_TOKEN: u64 = 1;
+
+/// Default number of consecutive unanswered pings before a session is
+/// considered timed-out. A single slow pong (transient jitter) should not
+/// kill an otherwise healthy peer, so we only report a timeout after this
+/// many probes in a row go unanswered.
+pub const FAILED_PING_THRESHOLD: usize = 4;
+
+/// Weight of the newest RTT sample in the exponentially-weighted moving
+/// average (the classic `1/8` used by TCP's smoothed RTT estimator).
+const RTT_SMOOTHING_FACTOR: u32 = 8;
 
 /// Ping protocol events
 #[derive(Debug)]
 pub enum Event {
     /// Peer send ping to us.
     Ping(PeerId),
-    /// Peer send pong to us.
-    Pong(PeerId, Duration),
+    /// Peer send pong to us, carrying the instantaneous RTT and the
+    /// exponentially-weighted moving average of the RTT for this session.
+    Pong(PeerId, Duration, Duration),
     /// Peer is timeout.
     Timeout(PeerId),
     /// Peer cause a unexpected error.
@@ -39,8 +49,12 @@ pub struct PingProtocol<S: Sender<Event> + Send + Clone> {
     id: ProtocolId,
     /// the interval that we send ping to peers.
     interval: Duration,
-    /// consider peer is timeout if during a timeout we still have not received pong from a peer
+    /// consider a single probe failed if we still have not received a pong
+    /// from a peer after this duration.
     timeout: Duration,
+    /// number of consecutive failed probes before a session is reported
+    /// timed-out.
+    threshold: usize,
     event_sender: S,
 }
 
@@ -48,11 +62,18 @@ impl<S> PingProtocol<S>
 where
     S: Sender<Event> + Send + Clone,
 {
-    pub fn new(id: ProtocolId, interval: Duration, timeout: Duration, event_sender: S) -> Self {
+    pub fn new(
+        id: ProtocolId,
+        interval: Duration,
+        timeout: Duration,
+        threshold: usize,
+        event_sender: S,
+    ) -> Self {
         PingProtocol {
             id,
             interval,
             timeout,
+            threshold,
             event_sender,
         }
     }
@@ -74,6 +95,7 @@ where
             proto_id: self.id,
             interval: self.interval,
             timeout: self.timeout,
+            threshold: self.threshold,
             connected_session_ids: Default::default(),
             event_sender: self.event_sender.clone(),
         });
@@ -85,6 +107,7 @@ struct PingHandler<S: Sender<Event>> {
     proto_id: ProtocolId,
     interval: Duration,
     timeout: Duration,
+    threshold: usize,
     connected_session_ids: FnvHashMap<SessionId, PingStatus>,
     event_sender: S,
 }
@@ -97,6 +120,17 @@ impl<S: Sender<Event>> PingHandler<S> {
     }
 }
 
+/// What a send tick should do with a single peer's probe.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum ProbeOutcome {
+    /// The outstanding probe is still within its timeout; do nothing.
+    Waiting,
+    /// Send a fresh probe to this peer.
+    Send,
+    /// Send a fresh probe and report the session as timed-out.
+    SendAndTimeout,
+}
+
 /// PingStatus of a peer
 #[derive(Clone, Debug)]
 struct PingStatus {
@@ -104,6 +138,15 @@ struct PingStatus {
     processing: bool,
     /// The time we last send ping to this peer.
     last_ping: SystemTime,
+    /// The time we last send a probe to this peer. Tracked independently of
+    /// the send interval so the timeout check measures a single outstanding
+    /// probe rather than the whole ping cadence.
+    last_send: SystemTime,
+    /// Number of consecutive probes that went unanswered past `timeout`.
+    failed_pings: usize,
+    /// Exponentially-weighted moving average of the measured RTT, updated on
+    /// every valid pong. `None` until the first pong arrives.
+    rtt_ewma: Option<Duration>,
     peer_id: PeerId,
 }
 
@@ -120,6 +163,51 @@ impl PingStatus {
     fn elapsed(&self) -> Duration {
         self.last_ping.elapsed().unwrap_or(Duration::from_secs(0))
     }
+
+    /// Time duration since we last send a probe to this peer.
+    fn send_elapsed(&self) -> Duration {
+        self.last_send.elapsed().unwrap_or(Duration::from_secs(0))
+    }
+
+    /// Decide what to do with this peer's probe at a send tick.
+    ///
+    /// An outstanding probe still inside its `timeout` window is left
+    /// untouched ([`ProbeOutcome::Waiting`]) so a late-but-valid pong still
+    /// matches its nonce. Once a probe exceeds `timeout` it is counted as a
+    /// failure and replaced with a fresh one; after `threshold` consecutive
+    /// failures the session is reported timed-out.
+    fn poll(&mut self, now: SystemTime, timeout: Duration, threshold: usize) -> ProbeOutcome {
+        if self.processing {
+            if self.send_elapsed() < timeout {
+                return ProbeOutcome::Waiting;
+            }
+            self.failed_pings += 1;
+        }
+        self.processing = true;
+        self.last_ping = now;
+        self.last_send = now;
+        if self.failed_pings >= threshold {
+            ProbeOutcome::SendAndTimeout
+        } else {
+            ProbeOutcome::Send
+        }
+    }
+
+    /// Fold a fresh RTT `sample` into the moving average and return the
+    /// smoothed value.
+    fn update_rtt(&mut self, sample: Duration) -> Duration {
+        let smoothed = match self.rtt_ewma {
+            // smoothed = smoothed + (sample - smoothed) / N
+            Some(prev) => {
+                let prev = prev.as_secs_f64();
+                let next = prev + (sample.as_secs_f64() - prev) / f64::from(RTT_SMOOTHING_FACTOR);
+                Duration::from_secs_f64(next)
+            }
+            None => sample,
+        };
+        self.rtt_ewma = Some(smoothed);
+        smoothed
+    }
 }
 
 impl<S> ServiceProtocol for PingHandler<S>
@@ -127,9 +215,9 @@ where
     S: Sender<Event>,
 {
     fn init(&mut self, control: &mut ServiceContext) {
-        // periodicly send ping to peers
+        // periodicly send ping to peers; the timeout deadline is evaluated
+        // against each outstanding probe at the next interval tick.
         control.set_service_notify(self.proto_id, self.interval, SEND_PING_TOKEN);
-        control.set_service_notify(self.proto_id, self.timeout, CHECK_TIMEOUT_TOKEN);
     }
 
     fn connected(&mut self, control: &mut ServiceContext, session: &SessionContext, version: &str) {
@@ -140,7 +228,10 @@ where
                     .entry(session.id)
                     .or_insert_with(|| PingStatus {
                         last_ping: SystemTime::now(),
+                        last_send: SystemTime::now(),
                         processing: false,
+                        failed_pings: 0,
+                        rtt_ewma: None,
                         peer_id,
                     });
                 debug!(
@@ -188,14 +279,18 @@ where
                         .map(|ps| (ps.processing, ps.nonce()))
                         == Some((true, pong_msg.nonce()))
                     {
-                        let ping_time = match self.connected_session_ids.get_mut(&session.id) {
-                            Some(ps) => {
-                                ps.processing = false;
-                                ps.elapsed()
-                            }
-                            None => return,
-                        };
-                        self.send_event(Event::Pong(peer_id, ping_time));
+                        let (ping_time, smoothed) =
+                            match self.connected_session_ids.get_mut(&session.id) {
+                                Some(ps) => {
+                                    ps.processing = false;
+                                    // a valid pong clears the consecutive-failure count
+                                    ps.failed_pings = 0;
+                                    let rtt = ps.elapsed();
+                                    (rtt, ps.update_rtt(rtt))
+                                }
+                                None => return,
+                            };
+                        self.send_event(Event::Pong(peer_id, ping_time, smoothed));
                     } else {
                         // ignore if nonce is incorrect
                         self.send_event(Event::UnexpectedError(peer_id));
@@ -214,19 +309,31 @@ where
             SEND_PING_TOKEN => {
                 debug!("proto [{}] start ping peers", self.proto_id);
                 let now = SystemTime::now();
-                let peers: Vec<(SessionId, u32)> = self
-                    .connected_session_ids
-                    .iter_mut()
-                    .filter_map(|(session_id, ps)| {
-                        if ps.processing {
-                            None
-                        } else {
-                            ps.processing = true;
-                            ps.last_ping = now;
-                            Some((*session_id, ps.nonce()))
+                let timeout = self.timeout;
+                let threshold = self.threshold;
+                // A single probe per session is tracked until it is answered
+                // or times out; we never re-stamp an outstanding probe, so its
+                // age is measured against `timeout` independently of the send
+                // interval (which may be shorter). A pong that arrives within
+                // `timeout` still matches the outstanding nonce — the transient
+                // jitter this threshold exists to tolerate. Only once a probe
+                // exceeds `timeout` is it counted a failure and replaced; after
+                // `threshold` consecutive failures the session is timed-out.
+                let mut timed_out = Vec::new();
+                let mut peers: Vec<(SessionId, u32)> = Vec::new();
+                for (session_id, ps) in self.connected_session_ids.iter_mut() {
+                    match ps.poll(now, timeout, threshold) {
+                        ProbeOutcome::Waiting => {}
+                        ProbeOutcome::Send => peers.push((*session_id, ps.nonce())),
+                        ProbeOutcome::SendAndTimeout => {
+                            peers.push((*session_id, ps.nonce()));
+                            timed_out.push(ps.peer_id.clone());
                         }
-                    })
-                    .collect();
+                    }
+                }
+                for peer_id in timed_out {
+                    self.send_event(Event::Timeout(peer_id));
+                }
                 if !peers.is_empty() {
                     let mut fbb = FlatBufferBuilder::new();
                     let msg = PingMessage::build_ping(&mut fbb, peers[0].1);
@@ -242,20 +349,87 @@ where
                     );
                 }
             }
-            CHECK_TIMEOUT_TOKEN => {
-                debug!("proto [{}] check ping timeout", self.proto_id);
-                let timeout = self.timeout;
-                for peer_id in self
-                    .connected_session_ids
-                    .values()
-                    .filter(|ps| ps.processing && ps.elapsed() >= timeout)
-                    .map(|ps| ps.peer_id.clone())
-                    .collect::<Vec<PeerId>>()
-                {
-                    self.send_event(Event::Timeout(peer_id));
-                }
-            }
             _ => panic!("unknown token {}", token),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> PingStatus {
+        PingStatus {
+            processing: false,
+            last_ping: SystemTime::now(),
+            last_send: SystemTime::now(),
+            failed_pings: 0,
+            rtt_ewma: None,
+            peer_id: PeerId::random(),
+        }
+    }
+
+    #[test]
+    fn first_rtt_sample_seeds_the_average() {
+        let mut ps = status();
+        let sample = Duration::from_millis(40);
+        assert_eq!(ps.update_rtt(sample), sample);
+        assert_eq!(ps.rtt_ewma, Some(sample));
+    }
+
+    #[test]
+    fn rtt_average_moves_toward_new_samples() {
+        let mut ps = status();
+        ps.update_rtt(Duration::from_millis(40));
+        // A slower sample pulls the average up, but only by 1/N of the gap.
+        let smoothed = ps.update_rtt(Duration::from_millis(120));
+        assert!(smoothed > Duration::from_millis(40));
+        assert!(smoothed < Duration::from_millis(120));
+        // 40 + (120 - 40) / 8 == 50ms
+        assert_eq!(smoothed, Duration::from_millis(50));
+    }
+
+    #[test]
+    fn first_tick_sends_a_probe() {
+        let mut ps = status();
+        assert_eq!(
+            ps.poll(SystemTime::now(), Duration::from_secs(20), 4),
+            ProbeOutcome::Send
+        );
+        assert!(ps.processing);
+    }
+
+    #[test]
+    fn outstanding_probe_within_timeout_keeps_waiting() {
+        let mut ps = status();
+        let timeout = Duration::from_secs(20);
+        ps.poll(SystemTime::now(), timeout, 4);
+        let nonce_before = ps.nonce();
+        // A tick one interval (15s < 20s timeout) later must not re-probe, so a
+        // late-but-valid pong still matches the outstanding nonce.
+        ps.last_send = SystemTime::now() - Duration::from_secs(15);
+        assert_eq!(ps.poll(SystemTime::now(), timeout, 4), ProbeOutcome::Waiting);
+        assert_eq!(ps.failed_pings, 0);
+        assert_eq!(ps.nonce(), nonce_before);
+    }
+
+    #[test]
+    fn timeout_fires_only_after_threshold_failures() {
+        let mut ps = status();
+        let timeout = Duration::from_secs(20);
+        let threshold = 4;
+        // The first tick sends the first probe; nothing has failed yet.
+        ps.poll(SystemTime::now(), timeout, threshold);
+        // Drive four consecutive probes that each age past the timeout.
+        for n in 1..=threshold {
+            ps.last_send = SystemTime::now() - Duration::from_secs(21);
+            let outcome = ps.poll(SystemTime::now(), timeout, threshold);
+            assert_eq!(ps.failed_pings, n);
+            if n < threshold {
+                assert_eq!(outcome, ProbeOutcome::Send);
+            } else {
+                assert_eq!(outcome, ProbeOutcome::SendAndTimeout);
+            }
+        }
+    }
+}
@@ -0,0 +1,14 @@
+//! Secio transport support.
+//!
+//! This snapshot carries the in-session key-rotation state machine; the
+//! handshake and stream cipher live in the rest of the crate. The rotation
+//! module is wired in here so [`rotation::RotationState`] and the
+//! frame-tagging helpers are reachable from the transport's encrypt/decrypt
+//! path rather than compiling into nothing.
+
+pub mod rotation;
+
+pub use crate::rotation::{
+    decode_material, encode_material, tag_frame, untag_frame, FrameType, KeyMaterial,
+    RotationError, RotationState,
+};
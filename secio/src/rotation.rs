@@ -0,0 +1,403 @@
+//! Periodic in-session key rotation for the secio transport.
+//!
+//! By default a session negotiates one symmetric key during its secio
+//! handshake and keeps it for the connection's whole lifetime. This module
+//! adds optional forward secrecy: on a timer (driven by the service's
+//! `every_second`-style tick) the active side derives a fresh symmetric key,
+//! authenticates the new keying material under the *current* key, sends it as
+//! a dedicated rotation control frame, and then switches. The previous key is
+//! kept valid for a short overlap window so frames already in flight still
+//! decrypt.
+//!
+//! A one-byte message type is prepended to every frame so a rotation control
+//! frame is distinguishable from ordinary data, and a monotonically
+//! increasing `rotate_counter` lets both peers agree on which key index is
+//! active. Rotation is opt-in: an interval of `0` disables it and preserves
+//! the original single-key behavior.
+
+use std::time::{Duration, Instant};
+
+/// Prepended to every secio frame to distinguish control traffic from data.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FrameType {
+    /// An ordinary encrypted data frame.
+    Data,
+    /// A rotation control frame carrying new keying material.
+    Rotation,
+}
+
+impl FrameType {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            FrameType::Data => 0,
+            FrameType::Rotation => 1,
+        }
+    }
+
+    pub fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(FrameType::Data),
+            1 => Some(FrameType::Rotation),
+            _ => None,
+        }
+    }
+}
+
+/// The symmetric key material used for one rotation generation.
+#[derive(Clone)]
+pub struct KeyMaterial {
+    /// Which rotation generation this key belongs to.
+    pub index: u64,
+    /// The raw symmetric key bytes.
+    pub key: Vec<u8>,
+}
+
+/// Rotation state machine held by each side of a secio session.
+///
+/// Create one per session with [`RotationState::new`]. When rotation is
+/// disabled (`interval == 0`) [`RotationState::every_second`] never rotates,
+/// so the session keeps its handshake key unless
+/// [`RotationState::apply_remote`] is given material from the remote.
+pub struct RotationState {
+    /// Whether this side drives rotation. Exactly one peer rotates so the two
+    /// ends never both self-increment to the same index (`apply_remote` treats
+    /// an index equal to its own as a duplicate and keeps its own key, so the
+    /// two ends would hold different keys under one index). The elected rotator calls `every_second`; the
+    /// other side only ever `apply_remote`s what it is told.
+    rotator: bool,
+    /// How often to rotate; `Duration::ZERO` disables rotation.
+    interval: Duration,
+    /// How long the previous key stays valid after a switch.
+    overlap: Duration,
+    /// The counter both peers use to agree on the active key index.
+    rotate_counter: u64,
+    /// The currently active key.
+    current: KeyMaterial,
+    /// The previous key, retained until `previous_expires_at`.
+    previous: Option<KeyMaterial>,
+    /// When the previous key stops being accepted.
+    previous_expires_at: Option<Instant>,
+    /// When we last rotated; the next rotation is due `interval` after this.
+    last_rotate: Instant,
+}
+
+impl RotationState {
+    /// Create a rotation state seeded with the key negotiated during the
+    /// secio handshake. `interval` of `Duration::ZERO` disables rotation.
+    ///
+    /// `rotator` must be `true` on exactly one of the two peers — typically
+    /// the secio handshake initiator (dialer) — so only one side advances the
+    /// key index. The responder passes `false` and merely applies the
+    /// rotations it receives.
+    pub fn new(
+        initial_key: Vec<u8>,
+        rotator: bool,
+        interval: Duration,
+        overlap: Duration,
+        now: Instant,
+    ) -> Self {
+        RotationState {
+            rotator,
+            interval,
+            overlap,
+            rotate_counter: 0,
+            current: KeyMaterial {
+                index: 0,
+                key: initial_key,
+            },
+            previous: None,
+            previous_expires_at: None,
+            last_rotate: now,
+        }
+    }
+
+    /// Whether rotation is enabled for this session.
+    pub fn enabled(&self) -> bool {
+        !self.interval.is_zero()
+    }
+
+    /// The counter value both peers use to identify the active key.
+    pub fn rotate_counter(&self) -> u64 {
+        self.rotate_counter
+    }
+
+    /// Tick the timer. If this side is the rotator, rotation is enabled and at least `interval` has
+    /// elapsed since the last switch, derive a fresh key via `derive`, install
+    /// it as current (retaining the old key for the overlap window), and
+    /// return the new [`KeyMaterial`]. The caller serializes it with
+    /// [`encode_material`], tags it [`FrameType::Rotation`] via [`tag_frame`]
+    /// and sends it through the transport's normal encrypt path, so the new
+    /// material is encrypted and MAC'd under the *current* key — that is where
+    /// its authentication comes from. Returns `None` when nothing is due.
+    pub fn every_second<F>(&mut self, now: Instant, derive: F) -> Option<KeyMaterial>
+    where
+        F: FnOnce(u64) -> Vec<u8>,
+    {
+        // Only the elected rotator advances the key index; the responder never
+        // self-increments, so the two ends can never diverge.
+        if !self.rotator || !self.enabled() || now.duration_since(self.last_rotate) < self.interval
+        {
+            self.expire_previous(now);
+            return None;
+        }
+
+        let next_index = self.current.index + 1;
+        let fresh = KeyMaterial {
+            index: next_index,
+            key: derive(next_index),
+        };
+
+        // Keep the outgoing-but-soon-stale key alive for the overlap window so
+        // in-flight frames still decrypt.
+        self.previous = Some(self.current.clone());
+        self.previous_expires_at = Some(now + self.overlap);
+        self.current = fresh.clone();
+        self.rotate_counter = next_index;
+        self.last_rotate = now;
+        Some(fresh)
+    }
+
+    /// Accept keying material announced by the remote in a rotation control
+    /// frame, switching the active key to `material` and retaining the prior
+    /// key for the overlap window.
+    ///
+    /// Any index *ahead* of the current one is accepted — a dropped or
+    /// reordered rotation frame simply makes the next frame jump the index
+    /// forward, and we catch up rather than bricking the session on a strict
+    /// `+1` check. A duplicate of the current index is idempotent. Only a
+    /// genuinely *stale* index (older than the current one) is rejected, and
+    /// even then it is safe to ignore.
+    pub fn apply_remote(&mut self, material: KeyMaterial, now: Instant) -> Result<(), RotationError> {
+        use std::cmp::Ordering;
+        match material.index.cmp(&self.current.index) {
+            Ordering::Greater => {
+                self.previous = Some(self.current.clone());
+                self.previous_expires_at = Some(now + self.overlap);
+                self.rotate_counter = material.index;
+                self.current = material;
+                Ok(())
+            }
+            Ordering::Equal => Ok(()),
+            Ordering::Less => Err(RotationError::StaleIndex {
+                current: self.current.index,
+                got: material.index,
+            }),
+        }
+    }
+
+    /// The keys a frame may be decrypted with: the current key always, plus
+    /// the previous key while still inside the overlap window.
+    pub fn decrypt_keys(&self, now: Instant) -> Vec<&KeyMaterial> {
+        let mut keys = vec![&self.current];
+        if let (Some(prev), Some(expires)) = (self.previous.as_ref(), self.previous_expires_at) {
+            if now < expires {
+                keys.push(prev);
+            }
+        }
+        keys
+    }
+
+    /// The key used to encrypt outgoing frames (always the current key).
+    pub fn encrypt_key(&self) -> &KeyMaterial {
+        &self.current
+    }
+
+    fn expire_previous(&mut self, now: Instant) {
+        if let Some(expires) = self.previous_expires_at {
+            if now >= expires {
+                self.previous = None;
+                self.previous_expires_at = None;
+            }
+        }
+    }
+}
+
+/// Errors surfaced while applying a rotation announced by the remote.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum RotationError {
+    /// The remote announced an index older than the one we already hold, so
+    /// the frame is stale and can be dropped.
+    StaleIndex { current: u64, got: u64 },
+    /// A frame did not carry a recognised [`FrameType`] byte.
+    BadFrameType,
+    /// A rotation control frame was truncated or malformed.
+    MalformedFrame,
+}
+
+/// Serialize keying material as the payload of a rotation control frame.
+///
+/// The frame is sent through the transport's normal encrypt path, so it is
+/// encrypted and MAC'd under the *current* key exactly like any data frame —
+/// that is where authentication of the new material comes from. Here we only
+/// lay out the plaintext payload: the new key index followed by the raw key
+/// bytes.
+pub fn encode_material(material: &KeyMaterial) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(8 + material.key.len());
+    buf.extend_from_slice(&material.index.to_be_bytes());
+    buf.extend_from_slice(&material.key);
+    buf
+}
+
+/// Parse the payload of a rotation control frame produced by
+/// [`encode_material`]. The caller must have already verified the enclosing
+/// secio frame's MAC under the current key before trusting this material.
+pub fn decode_material(payload: &[u8]) -> Result<KeyMaterial, RotationError> {
+    if payload.len() < 8 {
+        return Err(RotationError::MalformedFrame);
+    }
+    let mut idx = [0u8; 8];
+    idx.copy_from_slice(&payload[..8]);
+    Ok(KeyMaterial {
+        index: u64::from_be_bytes(idx),
+        key: payload[8..].to_vec(),
+    })
+}
+
+/// Prepend the [`FrameType`] byte to a secio payload before it is handed to
+/// the transport's encrypt path.
+pub fn tag_frame(ty: FrameType, mut payload: Vec<u8>) -> Vec<u8> {
+    let mut framed = Vec::with_capacity(payload.len() + 1);
+    framed.push(ty.to_byte());
+    framed.append(&mut payload);
+    framed
+}
+
+/// Split a decrypted frame into its [`FrameType`] and the remaining payload,
+/// so the transport can route rotation control frames to [`RotationState`] and
+/// data frames to the application.
+pub fn untag_frame(frame: &[u8]) -> Result<(FrameType, &[u8]), RotationError> {
+    match frame.split_first() {
+        Some((&byte, rest)) => FrameType::from_byte(byte)
+            .map(|ty| (ty, rest))
+            .ok_or(RotationError::BadFrameType),
+        None => Err(RotationError::BadFrameType),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(secs: u64) -> Instant {
+        // An instant `secs` seconds after the call; only used where any
+        // far-future instant will do.
+        Instant::now() + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn disabled_never_rotates() {
+        let base = Instant::now();
+        let mut state = RotationState::new(vec![0; 32], true, Duration::ZERO, Duration::ZERO, base);
+        assert!(!state.enabled());
+        assert!(state
+            .every_second(base + Duration::from_secs(3600), |i| vec![i as u8])
+            .is_none());
+    }
+
+    #[test]
+    fn only_the_elected_side_rotates() {
+        let base = Instant::now();
+        let interval = Duration::from_secs(10);
+        let overlap = Duration::from_secs(1);
+        let mut responder =
+            RotationState::new(vec![0; 32], false, interval, overlap, base);
+        // A non-rotator must not self-increment even once the interval passes.
+        assert!(responder
+            .every_second(base + Duration::from_secs(30), |i| vec![i as u8])
+            .is_none());
+        assert_eq!(responder.rotate_counter(), 0);
+    }
+
+    #[test]
+    fn rotator_and_responder_stay_in_agreement() {
+        let base = Instant::now();
+        let interval = Duration::from_secs(10);
+        let overlap = Duration::from_secs(2);
+        let mut rotator = RotationState::new(vec![0; 32], true, interval, overlap, base);
+        let mut responder = RotationState::new(vec![0; 32], false, interval, overlap, base);
+
+        let material = rotator
+            .every_second(base + Duration::from_secs(11), |i| vec![i as u8])
+            .expect("rotation due");
+        assert_eq!(material.index, 1);
+        responder.apply_remote(material, base + Duration::from_secs(11)).unwrap();
+        assert_eq!(rotator.rotate_counter(), responder.rotate_counter());
+        assert_eq!(rotator.encrypt_key().index, responder.encrypt_key().index);
+    }
+
+    #[test]
+    fn forward_jump_resyncs_after_dropped_frame() {
+        let base = Instant::now();
+        let mut responder = RotationState::new(
+            vec![0; 32],
+            false,
+            Duration::from_secs(10),
+            Duration::from_secs(1),
+            base,
+        );
+        // Rotation frame for index 1 was lost; index 2 arrives. We catch up
+        // rather than bricking the session.
+        responder
+            .apply_remote(KeyMaterial { index: 2, key: vec![2] }, base)
+            .unwrap();
+        assert_eq!(responder.rotate_counter(), 2);
+        // A duplicate of the current index is idempotent.
+        responder
+            .apply_remote(KeyMaterial { index: 2, key: vec![2] }, base)
+            .unwrap();
+        // A genuinely stale index is reported (and safe to drop).
+        let err = responder
+            .apply_remote(KeyMaterial { index: 1, key: vec![1] }, base)
+            .unwrap_err();
+        assert_eq!(err, RotationError::StaleIndex { current: 2, got: 1 });
+    }
+
+    #[test]
+    fn rotation_frame_round_trips_over_the_transport() {
+        let base = Instant::now();
+        let interval = Duration::from_secs(10);
+        let overlap = Duration::from_secs(2);
+        let mut rotator = RotationState::new(vec![0; 32], true, interval, overlap, base);
+        let mut responder = RotationState::new(vec![0; 32], false, interval, overlap, base);
+
+        // Rotator produces material, serializes and tags it as the transport
+        // would before encrypting.
+        let material = rotator
+            .every_second(base + Duration::from_secs(11), |i| vec![i as u8; 16])
+            .unwrap();
+        let frame = tag_frame(FrameType::Rotation, encode_material(&material));
+
+        // Responder receives the (decrypted) frame, routes it by type and
+        // applies it.
+        let (ty, payload) = untag_frame(&frame).unwrap();
+        assert_eq!(ty, FrameType::Rotation);
+        let decoded = decode_material(payload).unwrap();
+        responder.apply_remote(decoded, base + Duration::from_secs(11)).unwrap();
+
+        assert_eq!(rotator.encrypt_key().index, responder.encrypt_key().index);
+        assert_eq!(rotator.encrypt_key().key, responder.encrypt_key().key);
+    }
+
+    #[test]
+    fn previous_key_valid_during_overlap_only() {
+        let base = Instant::now();
+        let overlap = Duration::from_secs(2);
+        let mut rotator =
+            RotationState::new(vec![0; 32], true, Duration::from_secs(10), overlap, base);
+        let t = base + Duration::from_secs(11);
+        rotator.every_second(t, |i| vec![i as u8]).unwrap();
+        // Both keys decrypt inside the window...
+        assert_eq!(rotator.decrypt_keys(t).len(), 2);
+        // ...only the current key after it.
+        assert_eq!(rotator.decrypt_keys(at(3600)).len(), 1);
+    }
+
+    #[test]
+    fn frame_tagging_round_trips() {
+        let framed = tag_frame(FrameType::Rotation, vec![1, 2, 3]);
+        let (ty, payload) = untag_frame(&framed).unwrap();
+        assert_eq!(ty, FrameType::Rotation);
+        assert_eq!(payload, &[1, 2, 3]);
+        assert_eq!(untag_frame(&[]).unwrap_err(), RotationError::BadFrameType);
+    }
+}